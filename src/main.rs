@@ -1,14 +1,36 @@
-use clap::Parser;
+mod resampler;
+
+use chrono::Local;
+use clap::{Args, Parser, Subcommand};
 use cpal::traits::{DeviceTrait, HostTrait, StreamTrait};
-use cpal::{Device, FromSample, Stream, SupportedStreamConfig};
+use cpal::{Device, FromSample, Host, Stream, SupportedStreamConfig};
 use dasp_sample::ToSample;
+use resampler::Resampler;
+use ringbuf::traits::{Consumer, Producer, Split};
+use ringbuf::{HeapCons, HeapProd, HeapRb};
 use std::fs::File;
 use std::io::BufWriter;
-use std::sync::mpsc::{channel, Receiver, Sender};
+use std::sync::{Arc, Mutex};
 
 #[derive(Parser, Debug)]
 #[command(version, about = "record and echo audio inputs", long_about = None)]
 struct Opt {
+    #[command(subcommand)]
+    command: Command,
+}
+
+#[derive(Subcommand, Debug)]
+enum Command {
+    /// Record audio from an input device to a wav file
+    Record(RecordArgs),
+    /// Echo audio from an input device straight to an output device
+    Echo(EchoArgs),
+    /// Play back a wav file through an output device
+    Play(PlayArgs),
+}
+
+#[derive(Args, Debug)]
+struct RecordArgs {
     /// The audio device to use for recording
     #[arg(long, default_value = "default")]
     device_in: String,
@@ -17,33 +39,102 @@ struct Opt {
     #[arg(long, default_value = "default")]
     device_out: String,
 
-    /// The name of the file where to save audio
-    #[arg(long, default_value = "recording.wav")]
+    /// Prefix for the timestamped output filename, e.g. "<prefix>_2024-01-02_15-04-05.wav"
+    #[arg(long, default_value = "recording")]
+    wav_prefix: String,
+
+    /// Resample the recording to this rate (Hz) instead of the device's native rate
+    #[arg(long)]
+    resample_rate: Option<u32>,
+
+    /// Downmix the recording to a single channel
+    #[arg(long)]
+    mono: bool,
+
+    /// Size of the buffer between input and output streams, in milliseconds
+    #[arg(long, default_value_t = 200)]
+    latency_ms: u32,
+
+    /// Record for this many seconds instead of waiting for Enter
+    #[arg(long)]
+    duration: Option<f64>,
+
+    /// Capture system/loopback audio (what's playing) instead of a microphone
+    #[arg(long)]
+    loopback: bool,
+
+    /// Fixed input stream buffer size, in frames (defaults to the device's own choice)
+    #[arg(long)]
+    input_buffer_size: Option<u32>,
+
+    /// Fixed output stream buffer size, in frames (defaults to the device's own choice)
+    #[arg(long)]
+    output_buffer_size: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct EchoArgs {
+    /// The audio device to use for recording
+    #[arg(long, default_value = "default")]
+    device_in: String,
+
+    /// The audio device to use for playing
+    #[arg(long, default_value = "default")]
+    device_out: String,
+
+    /// Size of the buffer between input and output streams, in milliseconds
+    #[arg(long, default_value_t = 200)]
+    latency_ms: u32,
+
+    /// Fixed input stream buffer size, in frames (defaults to the device's own choice)
+    #[arg(long)]
+    input_buffer_size: Option<u32>,
+
+    /// Fixed output stream buffer size, in frames (defaults to the device's own choice)
+    #[arg(long)]
+    output_buffer_size: Option<u32>,
+}
+
+#[derive(Args, Debug)]
+struct PlayArgs {
+    /// The wav file to play
     wav: String,
+
+    /// The audio device to use for playing
+    #[arg(long, default_value = "default")]
+    device_out: String,
+
+    /// Fixed output stream buffer size, in frames (defaults to the device's own choice)
+    #[arg(long)]
+    output_buffer_size: Option<u32>,
+
+    /// Size of the buffer feeding the output device, in milliseconds
+    #[arg(long, default_value_t = 200)]
+    latency_ms: u32,
 }
 
 fn main() -> anyhow::Result<()> {
     let opt = Opt::parse();
-    let host = cpal::default_host();
+    match opt.command {
+        Command::Record(args) => run_record(args),
+        Command::Echo(args) => run_echo(args),
+        Command::Play(args) => run_play(args),
+    }
+}
 
-    // detect input device
-    let device = if opt.device_in == "default" {
-        host.default_input_device()
+fn run_record(opt: RecordArgs) -> anyhow::Result<()> {
+    let host = if opt.loopback {
+        loopback_host()?
     } else {
-        let devices = host.input_devices()?;
-        find_device(devices, &opt.device_in)
+        cpal::default_host()
     };
-    let device_in = device.expect("failed to find input device");
-    println!("Input device: {}", device_in.name()?);
-
-    // detect output device
-    let device = if opt.device_in == "default" {
-        host.default_output_device()
+    let device_in = if opt.loopback && opt.device_in == "default" {
+        default_loopback_device(&host)?
     } else {
-        let devices = host.output_devices()?;
-        find_device(devices, &opt.device_out)
+        find_input_device(&host, &opt.device_in)?
     };
-    let device_out = device.expect("failed to find output device");
+    println!("Input device: {}", device_in.name()?);
+    let device_out = find_output_device(&cpal::default_host(), &opt.device_out)?;
     println!("Output device: {}", device_out.name()?);
 
     let config = device_in
@@ -51,21 +142,93 @@ fn main() -> anyhow::Result<()> {
         .expect("failed to get default input config");
     println!("Default input config: {:?}", config);
 
-    let (send, recv) = channel::<f32>();
+    let out_rate = opt.resample_rate.unwrap_or(config.sample_rate().0);
+    let out_channels = if opt.mono { 1 } else { config.channels() as usize };
+    let resampler = Resampler::new(
+        config.sample_rate().0,
+        config.channels() as usize,
+        out_rate,
+        out_channels,
+    );
 
-    // The WAV file we're recording to.
-    let spec = wav_spec_from_config(&config);
-    let writer = hound::WavWriter::create(&opt.wav, spec)?;
-    let recorder = Recorder {
-        wav_writer: writer,
-        send,
-    };
+    let (producer, consumer) = make_ring(out_rate, resampler.out_channels(), opt.latency_ms);
+
+    // The WAV file we're recording to, sized to match the resampler's output.
+    let spec = wav_spec_from_config(out_rate, &resampler);
+    let wav_path = timestamped_wav_path(&opt.wav_prefix);
+    let writer = hound::WavWriter::create(&wav_path, spec)?;
+    println!("Recording to: {wav_path}");
+    let recorder = Arc::new(Mutex::new(Recorder {
+        wav_writer: Some(writer),
+        producer,
+        resampler,
+    }));
 
     // Run the input stream on a separate thread.
-    let stream_in = make_input_stream(config.clone(), device_in, recorder)?;
+    let stream_in = make_input_stream(
+        config.clone(),
+        device_in,
+        recorder.clone(),
+        opt.input_buffer_size,
+    )?;
     stream_in.play()?;
 
-    let stream_out = make_output_stream(config, device_out, recv)?;
+    // The monitor stream plays back whatever the ring carries, which is the
+    // resampler's output format, not the input device's native one.
+    let monitor_config = select_output_config(&device_out, out_channels, out_rate)?;
+    println!("Output stream config: {:?}", monitor_config);
+    let stream_out = make_output_stream(monitor_config, device_out, consumer, opt.output_buffer_size)?;
+    stream_out.play()?;
+
+    if let Some(duration) = opt.duration {
+        println!("Recording for {duration} seconds...");
+        std::thread::sleep(std::time::Duration::from_secs_f64(duration));
+    } else {
+        println!("Listening, press Enter to exit...");
+        _ = std::io::stdin().read_line(&mut String::new());
+    }
+
+    drop(stream_in);
+    drop(stream_out);
+    recorder.lock().unwrap().finalize()?;
+    Ok(())
+}
+
+fn run_echo(opt: EchoArgs) -> anyhow::Result<()> {
+    let host = cpal::default_host();
+    let device_in = find_input_device(&host, &opt.device_in)?;
+    println!("Input device: {}", device_in.name()?);
+    let device_out = find_output_device(&host, &opt.device_out)?;
+    println!("Output device: {}", device_out.name()?);
+
+    let config = device_in
+        .default_input_config()
+        .expect("failed to get default input config");
+    println!("Default input config: {:?}", config);
+
+    // No resampling or downmixing for a plain echo: channels and rate pass through unchanged.
+    let resampler = Resampler::new(
+        config.sample_rate().0,
+        config.channels() as usize,
+        config.sample_rate().0,
+        config.channels() as usize,
+    );
+    let (producer, consumer) = make_ring(config.sample_rate().0, resampler.out_channels(), opt.latency_ms);
+    let recorder = Arc::new(Mutex::new(Recorder {
+        wav_writer: None,
+        producer,
+        resampler,
+    }));
+
+    let stream_in = make_input_stream(
+        config.clone(),
+        device_in,
+        recorder.clone(),
+        opt.input_buffer_size,
+    )?;
+    stream_in.play()?;
+
+    let stream_out = make_output_stream(config, device_out, consumer, opt.output_buffer_size)?;
     stream_out.play()?;
 
     println!("Listening, press Enter to exit...");
@@ -73,10 +236,164 @@ fn main() -> anyhow::Result<()> {
 
     drop(stream_in);
     drop(stream_out);
-    // writer.finalize()?;
+    recorder.lock().unwrap().finalize()?;
+    Ok(())
+}
+
+fn run_play(opt: PlayArgs) -> anyhow::Result<()> {
+    let host = cpal::default_host();
+    let device_out = find_output_device(&host, &opt.device_out)?;
+    println!("Output device: {}", device_out.name()?);
+
+    let mut reader = hound::WavReader::open(&opt.wav)?;
+    let file_spec = reader.spec();
+    println!("File spec: {:?}", file_spec);
+
+    let out_config = device_out
+        .default_output_config()
+        .expect("failed to get default output config");
+    println!("Default output config: {:?}", out_config);
+
+    // Always mix to exactly the output stream's channel count, not just mono
+    // vs. not, so e.g. a mono file on a stereo device gets duplicated instead
+    // of handed to the stream one channel short.
+    let resampler = Resampler::new(
+        file_spec.sample_rate,
+        file_spec.channels as usize,
+        out_config.sample_rate().0,
+        out_config.channels() as usize,
+    );
+
+    let (producer, consumer) =
+        make_ring(out_config.sample_rate().0, resampler.out_channels(), opt.latency_ms);
+
+    let samples = read_samples_as_f32(&mut reader, file_spec)?;
+    let resampled = if resampler.is_noop() {
+        samples
+    } else {
+        let mut resampler = resampler;
+        let mut out = resampler.push(&samples);
+        out.extend(resampler.flush());
+        out
+    };
+
+    // Feed the ring buffer from a dedicated thread so playback starts as soon
+    // as the output stream is running, backing off when the buffer is full.
+    let feeder = std::thread::spawn(move || {
+        let mut offset = 0;
+        while offset < resampled.len() {
+            offset += producer.push_slice(&resampled[offset..]);
+            if offset < resampled.len() {
+                std::thread::sleep(std::time::Duration::from_millis(5));
+            }
+        }
+    });
+
+    let stream_out = make_output_stream(out_config, device_out, consumer, opt.output_buffer_size)?;
+    stream_out.play()?;
+
+    feeder.join().expect("playback feeder thread panicked");
+    // Let the ring buffer drain before tearing down the stream.
+    std::thread::sleep(std::time::Duration::from_millis(opt.latency_ms as u64));
+    drop(stream_out);
     Ok(())
 }
 
+fn read_samples_as_f32(
+    reader: &mut hound::WavReader<std::io::BufReader<File>>,
+    spec: hound::WavSpec,
+) -> anyhow::Result<Vec<f32>> {
+    use hound::SampleFormat::*;
+    let samples = match (spec.sample_format, spec.bits_per_sample) {
+        (Int, 8) => reader
+            .samples::<i8>()
+            .map(|s| s.map(|s| s.to_sample_()))
+            .collect::<Result<Vec<f32>, _>>()?,
+        (Int, 16) => reader
+            .samples::<i16>()
+            .map(|s| s.map(|s| s.to_sample_()))
+            .collect::<Result<Vec<f32>, _>>()?,
+        // hound returns 24-bit samples right-justified (magnitude ~2^23), but
+        // ToSample<f32> for i32 normalizes against the full i32 range
+        // (2^31), so without the shift every 24-bit file would play back
+        // ~256x too quiet.
+        (Int, 24) => reader
+            .samples::<i32>()
+            .map(|s| s.map(|s| (s << 8).to_sample_()))
+            .collect::<Result<Vec<f32>, _>>()?,
+        (Int, 32) => reader
+            .samples::<i32>()
+            .map(|s| s.map(|s| s.to_sample_()))
+            .collect::<Result<Vec<f32>, _>>()?,
+        (Float, 32) => reader
+            .samples::<f32>()
+            .collect::<Result<Vec<f32>, _>>()?,
+        (sample_format, bits_per_sample) => {
+            anyhow::bail!("Unsupported wav format '{sample_format:?}/{bits_per_sample}'")
+        }
+    };
+    Ok(samples)
+}
+
+/// Selects the host that can capture system/loopback audio. On macOS that's
+/// cpal's dedicated `ScreenCaptureKit` host; elsewhere, loopback/monitor
+/// devices are just regular input devices on the normal default host.
+#[cfg(target_os = "macos")]
+fn loopback_host() -> anyhow::Result<Host> {
+    cpal::host_from_id(cpal::HostId::ScreenCaptureKit)
+        .map_err(|err| anyhow::anyhow!("loopback capture is unavailable on this Mac: {err}"))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn loopback_host() -> anyhow::Result<Host> {
+    Ok(cpal::default_host())
+}
+
+/// Picks the system-audio capture device when `--device-in` was left as
+/// `default`. On macOS the `ScreenCaptureKit` host's default input device
+/// *is* the system mix; elsewhere we look for a monitor/loopback device by
+/// name among the regular input devices.
+#[cfg(target_os = "macos")]
+fn default_loopback_device(host: &Host) -> anyhow::Result<Device> {
+    host.default_input_device()
+        .ok_or_else(|| anyhow::anyhow!("failed to find a loopback capture device"))
+}
+
+#[cfg(not(target_os = "macos"))]
+fn default_loopback_device(host: &Host) -> anyhow::Result<Device> {
+    host.input_devices()?
+        .find(|device| {
+            device
+                .name()
+                .map(|name| name.to_lowercase().contains("monitor"))
+                .unwrap_or(false)
+        })
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "no loopback host or monitor input device is available on this platform; \
+                 pass --device-in with a monitor/loopback device name explicitly"
+            )
+        })
+}
+
+fn find_input_device(host: &Host, name: &str) -> anyhow::Result<Device> {
+    let device = if name == "default" {
+        host.default_input_device()
+    } else {
+        find_device(host.input_devices()?, name)
+    };
+    device.ok_or_else(|| anyhow::anyhow!("failed to find input device"))
+}
+
+fn find_output_device(host: &Host, name: &str) -> anyhow::Result<Device> {
+    let device = if name == "default" {
+        host.default_output_device()
+    } else {
+        find_device(host.output_devices()?, name)
+    };
+    device.ok_or_else(|| anyhow::anyhow!("failed to find output device"))
+}
+
 fn find_device<D: Iterator<Item = Device>>(devices: D, name: &str) -> Option<Device> {
     let mut names = Vec::new();
     for device in devices {
@@ -96,40 +413,109 @@ fn find_device<D: Iterator<Item = Device>>(devices: D, name: &str) -> Option<Dev
     None
 }
 
+/// Builds the ring buffer sitting between a producer (capture or file
+/// playback) and the output stream, sized from `--latency-ms`.
+fn make_ring(sample_rate: u32, channels: usize, latency_ms: u32) -> (HeapProd<f32>, HeapCons<f32>) {
+    let latency_frames = (sample_rate as usize * channels * latency_ms as usize) / 1000;
+    println!("Ring buffer capacity: {} samples", latency_frames);
+    let ring = HeapRb::<f32>::new(latency_frames.max(1));
+    ring.split()
+}
+
+/// Builds the `cpal::StreamConfig` to open a stream with, overriding the
+/// buffer size cpal would otherwise derive from `config` when the caller
+/// asked for a fixed one.
+fn stream_config(config: &SupportedStreamConfig, buffer_size: Option<u32>) -> cpal::StreamConfig {
+    let mut stream_config: cpal::StreamConfig = config.clone().into();
+    if let Some(size) = buffer_size {
+        stream_config.buffer_size = cpal::BufferSize::Fixed(size);
+    }
+    // This is what we asked for, not necessarily what cpal negotiates with
+    // the device; cpal has no generic API to read the size back afterwards,
+    // so only the requested value is printed.
+    println!("Requested buffer size: {:?}", stream_config.buffer_size);
+    stream_config
+}
+
+/// Finds an output config supporting exactly `channels` channels at `rate`,
+/// for building a monitor/output stream whose format must match data that's
+/// already been resampled/mixed rather than the device's own default config.
+fn select_output_config(
+    device: &Device,
+    channels: usize,
+    rate: u32,
+) -> anyhow::Result<SupportedStreamConfig> {
+    let sample_rate = cpal::SampleRate(rate);
+    device
+        .supported_output_configs()?
+        .find(|range| {
+            range.channels() as usize == channels
+                && range.min_sample_rate() <= sample_rate
+                && sample_rate <= range.max_sample_rate()
+        })
+        .map(|range| range.with_sample_rate(sample_rate))
+        .ok_or_else(|| {
+            anyhow::anyhow!(
+                "output device has no config supporting {channels} channel(s) at {rate} Hz"
+            )
+        })
+}
+
+/// Note: unlike the output callback (which only pops from a lock-free ring),
+/// this callback takes `recorder`'s mutex and, inside, allocates and runs the
+/// resampler — not a strictly lock-free/alloc-free real-time path. The mutex
+/// is uncontended in practice (nothing else holds it while the stream runs
+/// except a final `finalize()` after both streams are dropped), so this is
+/// an accepted tradeoff for the simpler `Recorder` ownership model, not a
+/// guarantee that the input side never blocks.
 fn make_input_stream(
     config: SupportedStreamConfig,
     device: Device,
-    mut recorder: Recorder,
+    recorder: Arc<Mutex<Recorder>>,
+    buffer_size: Option<u32>,
 ) -> anyhow::Result<Stream> {
     let err_fn = move |err| {
         eprintln!("an error occurred on stream: {}", err);
     };
+    let stream_config = stream_config(&config, buffer_size);
     use cpal::SampleFormat::*;
     let stream = match config.sample_format() {
-        I8 => device.build_input_stream(
-            &config.into(),
-            move |data, _: &_| recorder.read::<i8>(data),
-            err_fn,
-            None,
-        ),
-        I16 => device.build_input_stream(
-            &config.into(),
-            move |data, _: &_| recorder.read::<i16>(data),
-            err_fn,
-            None,
-        ),
-        I32 => device.build_input_stream(
-            &config.into(),
-            move |data, _: &_| recorder.read::<i32>(data),
-            err_fn,
-            None,
-        ),
-        F32 => device.build_input_stream(
-            &config.into(),
-            move |data, _: &_| recorder.read::<f32>(data),
-            err_fn,
-            None,
-        ),
+        I8 => {
+            let recorder = recorder.clone();
+            device.build_input_stream(
+                &stream_config,
+                move |data, _: &_| recorder.lock().unwrap().read::<i8>(data),
+                err_fn,
+                None,
+            )
+        }
+        I16 => {
+            let recorder = recorder.clone();
+            device.build_input_stream(
+                &stream_config,
+                move |data, _: &_| recorder.lock().unwrap().read::<i16>(data),
+                err_fn,
+                None,
+            )
+        }
+        I32 => {
+            let recorder = recorder.clone();
+            device.build_input_stream(
+                &stream_config,
+                move |data, _: &_| recorder.lock().unwrap().read::<i32>(data),
+                err_fn,
+                None,
+            )
+        }
+        F32 => {
+            let recorder = recorder.clone();
+            device.build_input_stream(
+                &stream_config,
+                move |data, _: &_| recorder.lock().unwrap().read::<f32>(data),
+                err_fn,
+                None,
+            )
+        }
         sample_format => {
             anyhow::bail!("Unsupported input sample format '{sample_format}'")
         }
@@ -137,55 +523,77 @@ fn make_input_stream(
     Ok(stream?)
 }
 
+/// Pops as many samples as are available from `consumer` into `scratch`
+/// (sized to match `output`) and fills any shortfall with silence, so the
+/// output callback never blocks waiting on the input side.
+fn fill_from_ring(consumer: &mut HeapCons<f32>, scratch: &mut [f32]) {
+    let filled = consumer.pop_slice(scratch);
+    for sample in &mut scratch[filled..] {
+        *sample = 0.0;
+    }
+}
+
 fn make_output_stream(
     config: SupportedStreamConfig,
     device: Device,
-    recv: Receiver<f32>,
+    mut consumer: HeapCons<f32>,
+    buffer_size: Option<u32>,
 ) -> anyhow::Result<Stream> {
     let err_fn = move |err| {
         eprintln!("an error occurred on stream: {}", err);
     };
+    let stream_config = stream_config(&config, buffer_size);
     use cpal::SampleFormat::*;
     let stream = match config.sample_format() {
-        I8 => device.build_output_stream(
-            &config.into(),
-            move |output: &mut [i8], _: &_| {
-                for sample in output.iter_mut() {
-                    let new_value = recv.recv().unwrap_or_default();
-                    *sample = i8::from_sample_(new_value);
-                }
-            },
-            err_fn,
-            None,
-        ),
-        I16 => device.build_output_stream(
-            &config.into(),
-            move |output: &mut [i16], _: &_| {
-                for sample in output.iter_mut() {
-                    let new_value = recv.recv().unwrap_or_default();
-                    *sample = i16::from_sample_(new_value);
-                }
-            },
-            err_fn,
-            None,
-        ),
-        I32 => device.build_output_stream(
-            &config.into(),
-            move |output: &mut [i32], _: &_| {
-                for sample in output.iter_mut() {
-                    let new_value = recv.recv().unwrap_or_default();
-                    *sample = i32::from_sample_(new_value);
-                }
-            },
-            err_fn,
-            None,
-        ),
+        I8 => {
+            let mut scratch = Vec::new();
+            device.build_output_stream(
+                &stream_config,
+                move |output: &mut [i8], _: &_| {
+                    scratch.resize(output.len(), 0.0);
+                    fill_from_ring(&mut consumer, &mut scratch);
+                    for (sample, &value) in output.iter_mut().zip(scratch.iter()) {
+                        *sample = i8::from_sample_(value);
+                    }
+                },
+                err_fn,
+                None,
+            )
+        }
+        I16 => {
+            let mut scratch = Vec::new();
+            device.build_output_stream(
+                &stream_config,
+                move |output: &mut [i16], _: &_| {
+                    scratch.resize(output.len(), 0.0);
+                    fill_from_ring(&mut consumer, &mut scratch);
+                    for (sample, &value) in output.iter_mut().zip(scratch.iter()) {
+                        *sample = i16::from_sample_(value);
+                    }
+                },
+                err_fn,
+                None,
+            )
+        }
+        I32 => {
+            let mut scratch = Vec::new();
+            device.build_output_stream(
+                &stream_config,
+                move |output: &mut [i32], _: &_| {
+                    scratch.resize(output.len(), 0.0);
+                    fill_from_ring(&mut consumer, &mut scratch);
+                    for (sample, &value) in output.iter_mut().zip(scratch.iter()) {
+                        *sample = i32::from_sample_(value);
+                    }
+                },
+                err_fn,
+                None,
+            )
+        }
         F32 => device.build_output_stream(
-            &config.into(),
+            &stream_config,
             move |output: &mut [f32], _: &_| {
-                for sample in output.iter_mut() {
-                    *sample = recv.recv().unwrap_or_default();
-                }
+                fill_from_ring(&mut consumer, output);
             },
             err_fn,
             None,
@@ -197,35 +605,72 @@ fn make_output_stream(
     Ok(stream?)
 }
 
-fn wav_spec_from_config(config: &cpal::SupportedStreamConfig) -> hound::WavSpec {
-    let sample_format = config.sample_format();
+/// Builds a filename like `<prefix>_2024-01-02_15-04-05.wav` from the local
+/// time, so repeated runs don't overwrite each other's recordings.
+fn timestamped_wav_path(prefix: &str) -> String {
+    let timestamp = Local::now().format("%Y-%m-%d_%H-%M-%S");
+    format!("{prefix}_{timestamp}.wav")
+}
+
+/// Builds the `WavSpec` for the *output* of the pipeline: the resampler may
+/// change the sample rate and channel count relative to the device config,
+/// and the file must reflect that, not the device's native format.
+fn wav_spec_from_config(out_rate: u32, resampler: &Resampler) -> hound::WavSpec {
     hound::WavSpec {
-        channels: config.channels(),
-        sample_rate: config.sample_rate().0,
-        bits_per_sample: (sample_format.sample_size() * 8) as u16,
-        sample_format: if sample_format.is_float() {
-            hound::SampleFormat::Float
-        } else {
-            hound::SampleFormat::Int
-        },
+        channels: resampler.out_channels() as u16,
+        sample_rate: out_rate,
+        bits_per_sample: 32,
+        sample_format: hound::SampleFormat::Float,
     }
 }
 
-/// Recorder receives audio from input, writes it into a wav file, and sends it to output.
+/// Recorder receives audio from input, resamples it, writes it into a wav
+/// file, and pushes it to the output ring buffer. Overflow is dropped
+/// silently so the real-time input callback never blocks.
+///
+/// `wav_writer` is `None` for a plain echo (nothing to save) and otherwise an
+/// `Option` so ownership can be taken out and the writer properly finalized
+/// in [`Recorder::finalize`], deterministically, whether the caller stopped
+/// recording via a timer or by pressing Enter.
 struct Recorder {
-    wav_writer: hound::WavWriter<BufWriter<File>>,
-    send: Sender<f32>,
+    wav_writer: Option<hound::WavWriter<BufWriter<File>>>,
+    producer: HeapProd<f32>,
+    resampler: Resampler,
 }
 
 impl Recorder {
     fn read<T>(&mut self, input: &[T])
     where
-        T: ToSample<f32> + hound::Sample + Copy,
+        T: ToSample<f32> + Copy,
     {
-        for &sample in input.iter() {
-            self.wav_writer.write_sample(sample).unwrap();
-            let float_sample: f32 = sample.to_sample_();
-            self.send.send(float_sample).unwrap();
+        let floats: Vec<f32> = input.iter().map(|&sample| sample.to_sample_()).collect();
+        let resampled = if self.resampler.is_noop() {
+            floats
+        } else {
+            self.resampler.push(&floats)
+        };
+        self.write_samples(&resampled);
+        _ = self.producer.push_slice(&resampled);
+    }
+
+    fn write_samples(&mut self, samples: &[f32]) {
+        if let Some(writer) = self.wav_writer.as_mut() {
+            for &sample in samples {
+                writer.write_sample(sample).unwrap();
+            }
+        }
+    }
+
+    /// Flushes the resampler's trailing partial block and finalizes the wav
+    /// file (if any), writing a correct header regardless of how recording
+    /// stopped.
+    fn finalize(&mut self) -> anyhow::Result<()> {
+        let flushed = self.resampler.flush();
+        self.write_samples(&flushed);
+        _ = self.producer.push_slice(&flushed);
+        if let Some(writer) = self.wav_writer.take() {
+            writer.finalize()?;
         }
+        Ok(())
     }
 }
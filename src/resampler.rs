@@ -0,0 +1,209 @@
+//! Streaming windowed-sinc resampler, modeled on rubato's `SincFixedIn` in
+//! spirit: it consumes interleaved `f32` input incrementally and emits
+//! interleaved output at a different sample rate and/or channel count.
+//!
+//! Unlike a block-at-a-time design, the sinc kernel's history carries across
+//! `push` calls (via a running global sample position plus a trimmed tail of
+//! not-yet-fully-consumed input), so taps near a push boundary still see the
+//! samples on the other side of it instead of being zero-padded away.
+
+/// Number of taps on each side of the sinc kernel center.
+const HALF_TAPS: i64 = 16;
+
+/// Converts interleaved `f32` samples from `in_rate`/`in_channels` to
+/// `out_rate`/`out_channels`.
+///
+/// Call [`Resampler::flush`] once at shutdown to emit the trailing samples
+/// that don't yet have a full kernel's worth of future context; those are
+/// zero-padded, as if the stream kept going with silence.
+pub struct Resampler {
+    in_rate: u32,
+    out_rate: u32,
+    in_channels: usize,
+    out_channels: usize,
+    /// Kernel cutoff relative to the input Nyquist: 1.0 when upsampling or
+    /// unity rate, `out_rate / in_rate` when downsampling, so the kernel
+    /// actually band-limits to the output Nyquist instead of aliasing.
+    cutoff: f64,
+    /// Kernel half-width in input samples, widened by `1 / cutoff` so a
+    /// lower cutoff doesn't also narrow the filter's time support.
+    kernel_half_taps: i64,
+    /// Total input frames (post channel-mixing) seen so far.
+    total_in_frames: u64,
+    /// Global index of the next output frame to produce.
+    next_out_frame: u64,
+    /// Global index corresponding to `mixed[ch][0]`.
+    buffer_start: u64,
+    /// Per-output-channel history of mixed (not yet fully consumed) samples.
+    mixed: Vec<Vec<f32>>,
+}
+
+impl Resampler {
+    pub fn new(in_rate: u32, in_channels: usize, out_rate: u32, out_channels: usize) -> Self {
+        let cutoff = (out_rate as f64 / in_rate as f64).min(1.0);
+        let kernel_half_taps = (HALF_TAPS as f64 / cutoff).ceil() as i64;
+        Resampler {
+            in_rate,
+            out_rate,
+            in_channels,
+            out_channels,
+            cutoff,
+            kernel_half_taps,
+            total_in_frames: 0,
+            next_out_frame: 0,
+            buffer_start: 0,
+            mixed: vec![Vec::new(); out_channels],
+        }
+    }
+
+    /// Passthrough check: no work to do if rates and channel counts already match.
+    pub fn is_noop(&self) -> bool {
+        self.in_rate == self.out_rate && self.in_channels == self.out_channels
+    }
+
+    pub fn out_channels(&self) -> usize {
+        self.out_channels
+    }
+
+    /// Feeds interleaved input samples, returning whatever interleaved
+    /// output frames now have enough future context to be finalized.
+    pub fn push(&mut self, input: &[f32]) -> Vec<f32> {
+        self.ingest(input);
+        self.drain(false)
+    }
+
+    /// Emits the remaining output frames, treating samples past the end of
+    /// the input as silence. Call once, on shutdown, after the last `push`.
+    pub fn flush(&mut self) -> Vec<f32> {
+        self.drain(true)
+    }
+
+    /// Deinterleaves and channel-mixes `input` into `self.mixed`, dropping a
+    /// short trailing frame (input length not a multiple of `in_channels`)
+    /// instead of assuming cpal always hands us whole frames.
+    fn ingest(&mut self, input: &[f32]) {
+        for frame in input.chunks(self.in_channels) {
+            if frame.len() < self.in_channels {
+                break;
+            }
+            self.mix_frame(frame);
+            self.total_in_frames += 1;
+        }
+    }
+
+    fn mix_frame(&mut self, frame: &[f32]) {
+        match (self.in_channels, self.out_channels) {
+            (i, o) if i == o => {
+                for (ch, &sample) in frame.iter().enumerate() {
+                    self.mixed[ch].push(sample);
+                }
+            }
+            (_, 1) => {
+                let avg = frame.iter().sum::<f32>() / frame.len() as f32;
+                self.mixed[0].push(avg);
+            }
+            (1, out_channels) => {
+                for buf in self.mixed.iter_mut().take(out_channels) {
+                    buf.push(frame[0]);
+                }
+            }
+            (_, out_channels) => {
+                // Arbitrary N-to-M remap: downmix to mono, then duplicate.
+                let avg = frame.iter().sum::<f32>() / frame.len() as f32;
+                for buf in self.mixed.iter_mut().take(out_channels) {
+                    buf.push(avg);
+                }
+            }
+        }
+    }
+
+    /// Produces every output frame whose kernel support is fully available.
+    /// During a `flush`, samples past `total_in_frames` read as silence so
+    /// the trailing partial window is emitted instead of withheld forever.
+    fn drain(&mut self, flushing: bool) -> Vec<f32> {
+        let ratio = self.out_rate as f64 / self.in_rate as f64;
+        let total = self.total_in_frames as i64;
+        let target_len = if flushing {
+            (self.total_in_frames as f64 * ratio).round() as u64
+        } else {
+            u64::MAX
+        };
+
+        let mut out_channels: Vec<Vec<f32>> = vec![Vec::new(); self.out_channels];
+        while self.next_out_frame < target_len {
+            let src_pos = self.next_out_frame as f64 / ratio;
+            let center = src_pos.floor() as i64;
+            if !flushing && center + self.kernel_half_taps - 1 >= total {
+                break; // not enough future input yet to finalize this frame
+            }
+            for (ch, out) in out_channels.iter_mut().enumerate() {
+                out.push(self.convolve(ch, center, src_pos, total));
+            }
+            self.next_out_frame += 1;
+        }
+        self.trim_consumed_history(ratio);
+        interleave(out_channels)
+    }
+
+    fn convolve(&self, channel: usize, center: i64, src_pos: f64, total: i64) -> f32 {
+        let mut acc = 0.0f64;
+        for tap in -self.kernel_half_taps..self.kernel_half_taps {
+            let idx = center + tap;
+            if idx < 0 || idx >= total {
+                continue; // silence before the stream start or past its end
+            }
+            let sample = self.mixed[channel][(idx as u64 - self.buffer_start) as usize];
+            acc += sample as f64
+                * windowed_sinc(src_pos - idx as f64, self.cutoff, self.kernel_half_taps as f64);
+        }
+        acc as f32
+    }
+
+    /// Drops history no future output frame's kernel can still reach.
+    fn trim_consumed_history(&mut self, ratio: f64) {
+        let next_src_pos = self.next_out_frame as f64 / ratio;
+        let keep_from =
+            ((next_src_pos.floor() as i64 - self.kernel_half_taps).max(self.buffer_start as i64)) as u64;
+        let drop = (keep_from - self.buffer_start) as usize;
+        if drop == 0 {
+            return;
+        }
+        for buf in &mut self.mixed {
+            let drop = drop.min(buf.len());
+            buf.drain(..drop);
+        }
+        self.buffer_start += drop as u64;
+    }
+}
+
+fn interleave(channels: Vec<Vec<f32>>) -> Vec<f32> {
+    let frames = channels.first().map_or(0, Vec::len);
+    let mut out = Vec::with_capacity(frames * channels.len());
+    for frame in 0..frames {
+        for ch in &channels {
+            out.push(ch[frame]);
+        }
+    }
+    out
+}
+
+/// Windowed-sinc low-pass kernel, scaled to cut off at `cutoff` times the
+/// input Nyquist (1.0 = no low-pass, used when upsampling or at unity rate).
+/// Scaling the sinc's argument by `cutoff` moves its first zero-crossing out
+/// to `1 / cutoff` input samples, so the Hann window is widened to `half`
+/// (the caller's `kernel_half_taps`) to keep covering it; the `cutoff` factor
+/// out front keeps the kernel's DC gain at 1 despite the narrower sinc.
+fn windowed_sinc(x: f64, cutoff: f64, half: f64) -> f64 {
+    if x.abs() >= half {
+        return 0.0;
+    }
+    let scaled = x * cutoff;
+    let sinc = if scaled.abs() < 1e-9 {
+        1.0
+    } else {
+        (std::f64::consts::PI * scaled).sin() / (std::f64::consts::PI * scaled)
+    };
+    // Hann window over the [-half, half] support.
+    let window = 0.5 * (1.0 + (std::f64::consts::PI * x / half).cos());
+    cutoff * sinc * window
+}